@@ -1,6 +1,7 @@
 use log::debug;
 use std::convert::TryFrom;
 use std::error::Error;
+use std::io;
 use std::io::{Read, Write};
 use std::net::{Shutdown, TcpStream, ToSocketAddrs};
 use std::sync::Arc;
@@ -9,6 +10,12 @@ use std::time::Duration;
 use rustls;
 use webpki_roots;
 
+use futures::stream::{self, StreamExt};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream as AsyncTcpStream;
+use tokio::time::timeout as tokio_timeout;
+use tokio_rustls::TlsConnector;
+
 use super::records;
 
 use crate::nts_ke::records::{
@@ -43,14 +50,86 @@ type Cookie = Vec<u8>;
 
 const DEFAULT_NTP_PORT: u16 = 123;
 const DEFAULT_KE_PORT: u16 = 4460;
-const DEFAULT_SCHEME: u16 = 0;
 const TIMEOUT: Duration = Duration::from_secs(15);
 
-#[derive(Debug)]
 pub struct ClientConfig {
     pub host: String,
     pub port: Option<u16>,
     pub use_ipv6: bool,
+    /// Next-protocols the client is willing to speak, in order of
+    /// preference (most preferred first). Sent to the server as-is; the
+    /// server picks the first one it also supports.
+    pub next_protocols: Vec<KnownNextProtocol>,
+    /// AEAD algorithms the client is willing to use, in order of preference
+    /// (most preferred first).
+    pub aead_schemes: Vec<KnownAeadAlgorithm>,
+    /// Shared TLS session storage. When set and reused across calls,
+    /// handshakes to the same host resume the previous TLS 1.3 session
+    /// instead of paying for a full handshake every time.
+    pub session_store: Option<Arc<dyn rustls::client::ClientSessionStore>>,
+    /// Client certificate chain and private key to present for mutual TLS.
+    /// When set, the client authenticates with this certificate instead of
+    /// skipping client auth.
+    pub client_auth: Option<(
+        Vec<rustls::pki_types::CertificateDer<'static>>,
+        rustls::pki_types::PrivateKeyDer<'static>,
+    )>,
+    /// Extra trust anchors added on top of the Mozilla root store bundled
+    /// via `webpki_roots`. Lets a deployment pin a private CA.
+    pub extra_root_certs: Vec<rustls::pki_types::CertificateDer<'static>>,
+    /// Explicit crypto backend to use instead of the process default
+    /// (`rustls::crypto::CryptoProvider::get_default()`).
+    pub crypto_provider: Option<Arc<rustls::crypto::CryptoProvider>>,
+}
+
+impl ClientConfig {
+    /// Builds a `ClientConfig` with the negotiation preferences cfnts has
+    /// always used (NTPv4 over AES-SIV-CMAC-256) and every other knob left
+    /// at its default (no session resumption, no client auth, no extra
+    /// trust anchors, process-default crypto provider). Existing callers
+    /// that only care about `host`/`port`/`use_ipv6` can use this instead
+    /// of writing out a struct literal that breaks every time this struct
+    /// grows a field.
+    pub fn new(host: String, port: Option<u16>, use_ipv6: bool) -> ClientConfig {
+        ClientConfig {
+            host,
+            port,
+            use_ipv6,
+            ..Default::default()
+        }
+    }
+}
+
+impl Default for ClientConfig {
+    fn default() -> ClientConfig {
+        ClientConfig {
+            host: String::new(),
+            port: None,
+            use_ipv6: false,
+            next_protocols: vec![KnownNextProtocol::Ntpv4],
+            aead_schemes: vec![KnownAeadAlgorithm::AeadAesSivCmac256],
+            session_store: None,
+            client_auth: None,
+            extra_root_certs: Vec::new(),
+            crypto_provider: None,
+        }
+    }
+}
+
+impl std::fmt::Debug for ClientConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ClientConfig")
+            .field("host", &self.host)
+            .field("port", &self.port)
+            .field("use_ipv6", &self.use_ipv6)
+            .field("next_protocols", &self.next_protocols)
+            .field("aead_schemes", &self.aead_schemes)
+            .field("session_store", &self.session_store.is_some())
+            .field("client_auth", &self.client_auth.is_some())
+            .field("extra_root_certs", &self.extra_root_certs.len())
+            .field("crypto_provider", &self.crypto_provider.is_some())
+            .finish()
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -64,16 +143,97 @@ pub struct NtsKeResult {
     pub use_ipv6: bool,
 }
 
-/// run_nts_client executes the nts client with the config in config file
-pub fn run_nts_ke_client(client_config: ClientConfig) -> Result<NtsKeResult, Box<dyn Error>> {
-    let alpn_proto = String::from("ntske/1");
-    let alpn_bytes = alpn_proto.into_bytes();
+/// Builds the `rustls::ClientConfig` shared by the sync and async NTS-KE
+/// clients: ALPN, trust anchors (Mozilla roots plus any `extra_root_certs`),
+/// optional mutual-TLS client auth, optional session resumption storage,
+/// and an optional explicit `CryptoProvider`.
+fn build_tls_config(
+    client_config: &ClientConfig,
+    alpn_bytes: Vec<u8>,
+) -> Result<rustls::ClientConfig, Box<dyn Error>> {
     let mut root_store = rustls::RootCertStore::empty();
     root_store.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
-    let mut tls_config = rustls::ClientConfig::builder()
-        .with_root_certificates(root_store)
-        .with_no_client_auth();
+    root_store.extend(client_config.extra_root_certs.iter().cloned());
+
+    let builder = match client_config.crypto_provider.clone() {
+        Some(provider) => rustls::ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .map_err(|err| Box::new(err) as Box<dyn Error>)?,
+        None => rustls::ClientConfig::builder(),
+    };
+    let builder = builder.with_root_certificates(root_store);
+    let mut tls_config = match &client_config.client_auth {
+        Some((cert_chain, key_der)) => builder
+            .with_client_auth_cert(cert_chain.clone(), key_der.clone_key())
+            .map_err(|err| Box::new(err) as Box<dyn Error>)?,
+        None => builder.with_no_client_auth(),
+    };
     tls_config.alpn_protocols = vec![alpn_bytes];
+    if let Some(session_store) = client_config.session_store.clone() {
+        tls_config.resumption = rustls::client::Resumption::store(session_store);
+    }
+    Ok(tls_config)
+}
+
+/// Picks the `KnownAeadAlgorithm` the key material should be exported for,
+/// failing loudly rather than silently guessing when the server selected a
+/// scheme the client doesn't recognize.
+fn negotiated_aead(aead_scheme: u16) -> Result<KnownAeadAlgorithm, Box<dyn Error>> {
+    KnownAeadAlgorithm::try_from(aead_scheme).map_err(|_| {
+        Box::new(io::Error::new(
+            io::ErrorKind::Other,
+            format!("server selected an unsupported AEAD scheme: {}", aead_scheme),
+        )) as Box<dyn Error>
+    })
+}
+
+/// Deserializes one already-framed NTS-KE record and folds it into `state`,
+/// the way both the sync and async read loops need to. Shared so that a
+/// future protocol fix (e.g. how an unknown critical record is handled)
+/// only has to be made once.
+fn handle_record(record_bytes: &[u8], state: &mut ReceivedNtsKeRecordState) -> Result<(), Box<dyn Error>> {
+    match deserialize(Party::Client, record_bytes) {
+        Ok(record) => process_record(record, state),
+        Err(DeserializeError::UnknownNotCriticalRecord) => {
+            // If it's not critical, just ignore the error.
+            debug!("unknown record type");
+            Ok(())
+        }
+        Err(DeserializeError::UnknownCriticalRecord) => {
+            // TODO: This should propertly handled by sending an Error record.
+            debug!("error: unknown critical record");
+            Err(Box::new(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                "unknown critical record",
+            )))
+        }
+        Err(DeserializeError::Parsing(error)) => {
+            // TODO: This shouldn't be wrapped as a trait object.
+            debug!("error: {}", error);
+            Err(Box::new(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                error,
+            )))
+        }
+    }
+}
+
+/// Pulls the aead scheme the server actually selected out of `state`,
+/// failing loudly instead of defaulting when it never sent one.
+fn require_negotiated_aead_scheme(state: &ReceivedNtsKeRecordState) -> Result<u16, Box<dyn Error>> {
+    match state.aead_scheme.first() {
+        Some(scheme) => Ok(*scheme),
+        None => Err(Box::new(io::Error::new(
+            io::ErrorKind::Other,
+            "server did not select an AEAD scheme",
+        ))),
+    }
+}
+
+/// run_nts_client executes the nts client with the config in config file
+pub fn run_nts_ke_client(client_config: ClientConfig) -> Result<NtsKeResult, Box<dyn Error>> {
+    let alpn_bytes = String::from("ntske/1").into_bytes();
+    let tls_config = build_tls_config(&client_config, alpn_bytes)?;
 
     let rc_config = Arc::new(tls_config);
     let hostname = rustls::pki_types::ServerName::try_from(client_config.host.as_str())
@@ -104,8 +264,8 @@ pub fn run_nts_ke_client(client_config: ClientConfig) -> Result<NtsKeResult, Box
 
     let mut tls_stream = rustls::Stream::new(&mut client, &mut stream);
 
-    let next_protocol_record = NextProtocolRecord::from(vec![KnownNextProtocol::Ntpv4]);
-    let aead_record = AeadAlgorithmRecord::from(vec![KnownAeadAlgorithm::AeadAesSivCmac256]);
+    let next_protocol_record = NextProtocolRecord::from(client_config.next_protocols.clone());
+    let aead_record = AeadAlgorithmRecord::from(client_config.aead_schemes.clone());
     let end_record = EndOfMessageRecord;
 
     let clientrec = &mut serialize(next_protocol_record);
@@ -114,7 +274,6 @@ pub fn run_nts_ke_client(client_config: ClientConfig) -> Result<NtsKeResult, Box
     tls_stream.write_all(clientrec)?;
     tls_stream.flush()?;
     debug!("Request transmitted");
-    let keys = records::gen_key(tls_stream.conn).unwrap();
 
     let mut state = ReceivedNtsKeRecordState {
         finished: false,
@@ -150,47 +309,125 @@ pub fn run_nts_ke_client(client_config: ClientConfig) -> Result<NtsKeResult, Box
         // `deserialize` has an invariant that the slice needs to be long enough to make it a
         // valid record, which in this case our slice is exactly as long as specified in the
         // length field.
-        match deserialize(Party::Client, record_bytes.as_slice()) {
-            Ok(record) => {
-                let status = process_record(record, &mut state);
-                match status {
-                    Ok(_) => {}
-                    Err(err) => {
-                        return Err(err);
-                    }
-                }
-            }
-            Err(DeserializeError::UnknownNotCriticalRecord) => {
-                // If it's not critical, just ignore the error.
-                debug!("unknown record type");
-            }
-            Err(DeserializeError::UnknownCriticalRecord) => {
-                // TODO: This should propertly handled by sending an Error record.
-                debug!("error: unknown critical record");
-                return Err(Box::new(std::io::Error::new(
-                    std::io::ErrorKind::Other,
-                    "unknown critical record",
-                )));
-            }
-            Err(DeserializeError::Parsing(error)) => {
-                // TODO: This shouldn't be wrapped as a trait object.
-                debug!("error: {}", error);
-                return Err(Box::new(std::io::Error::new(
-                    std::io::ErrorKind::Other,
-                    error,
-                )));
-            }
-        }
+        handle_record(record_bytes.as_slice(), &mut state)?;
     }
     debug!("saw the end of the response");
+
+    let aead_scheme = require_negotiated_aead_scheme(&state)?;
+
+    // The exported key material's length depends on the AEAD that was
+    // actually negotiated, so this has to happen after we've seen the
+    // server's response rather than right after sending the request. It
+    // also has to happen before `stream.shutdown` below: `tls_stream` holds
+    // `&mut stream` and `&mut client` under the same lifetime, so the raw
+    // `stream` can't be borrowed again while `tls_stream.conn` is still in
+    // use.
+    let keys = records::gen_key(tls_stream.conn, negotiated_aead(aead_scheme)?).unwrap();
+
     stream.shutdown(Shutdown::Write)?;
 
-    let aead_scheme = if state.aead_scheme.is_empty() {
-        DEFAULT_SCHEME
+    Ok(NtsKeResult {
+        aead_scheme,
+        cookies: state.cookies,
+        next_protocols: state.next_protocols,
+        next_server: state.next_server.unwrap_or(client_config.host.clone()),
+        next_port: state.next_port.unwrap_or(DEFAULT_NTP_PORT),
+        keys,
+        use_ipv6: client_config.use_ipv6,
+    })
+}
+
+/// Async counterpart of [`run_nts_ke_client`], built on tokio and
+/// tokio-rustls. Does the same handshake (same record framing, same
+/// `NtsKeResult`) but never blocks a thread, so it's cheap to run many of
+/// these concurrently against a pool of servers.
+///
+/// This only covers the NTS-KE half of the ask; the matching async NTP
+/// follow-up client belongs in `ntp::client`, which doesn't exist in this
+/// tree, so it isn't included here.
+pub async fn run_nts_ke_client_async(
+    client_config: ClientConfig,
+) -> Result<NtsKeResult, Box<dyn Error>> {
+    let alpn_bytes = String::from("ntske/1").into_bytes();
+    let tls_config = build_tls_config(&client_config, alpn_bytes)?;
+
+    let rc_config = Arc::new(tls_config);
+    let connector = TlsConnector::from(rc_config);
+    let hostname = rustls::pki_types::ServerName::try_from(client_config.host.as_str())
+        .expect("server hostname is invalid");
+    debug!("Connecting");
+    let port = client_config.port.unwrap_or(DEFAULT_KE_PORT);
+
+    let mut ip_addrs = tokio::net::lookup_host((client_config.host.as_str(), port)).await?;
+    let addr;
+    if client_config.use_ipv6 {
+        // mandated to use ipv6
+        addr = ip_addrs.find(|&x| x.is_ipv6());
+        if addr.is_none() {
+            return Err(Box::new(NtsKeParseError::NoIpv6AddrFound));
+        }
     } else {
-        state.aead_scheme[0]
+        // mandated to use ipv4
+        addr = ip_addrs.find(|&x| x.is_ipv4());
+        if addr.is_none() {
+            return Err(Box::new(NtsKeParseError::NoIpv4AddrFound));
+        }
+    }
+
+    let tcp_stream = tokio_timeout(TIMEOUT, AsyncTcpStream::connect(addr.unwrap())).await??;
+    let mut tls_stream =
+        tokio_timeout(TIMEOUT, connector.connect(hostname.to_owned(), tcp_stream)).await??;
+
+    let next_protocol_record = NextProtocolRecord::from(client_config.next_protocols.clone());
+    let aead_record = AeadAlgorithmRecord::from(client_config.aead_schemes.clone());
+    let end_record = EndOfMessageRecord;
+
+    let clientrec = &mut serialize(next_protocol_record);
+    clientrec.append(&mut serialize(aead_record));
+    clientrec.append(&mut serialize(end_record));
+    tokio_timeout(TIMEOUT, tls_stream.write_all(clientrec)).await??;
+    tokio_timeout(TIMEOUT, tls_stream.flush()).await??;
+    debug!("Request transmitted");
+
+    let mut state = ReceivedNtsKeRecordState {
+        finished: false,
+        next_protocols: Vec::new(),
+        aead_scheme: Vec::new(),
+        cookies: Vec::new(),
+        next_server: None,
+        next_port: None,
     };
 
+    while !state.finished {
+        let mut header: [u8; HEADER_SIZE] = [0; HEADER_SIZE];
+
+        // We should use `read_exact` here because we always need to read 4 bytes to get the
+        // header.
+        tokio_timeout(TIMEOUT, tls_stream.read_exact(&mut header[..])).await??;
+
+        // Retrieve a body length from the 3rd and 4th bytes of the header.
+        let body_length = u16::from_be_bytes([header[2], header[3]]);
+        let mut body = vec![0; body_length as usize];
+
+        // `read_exact` the length of the body.
+        tokio_timeout(TIMEOUT, tls_stream.read_exact(body.as_mut_slice())).await??;
+
+        // Reconstruct the whole record byte array to let the `records` module deserialize it.
+        let mut record_bytes = Vec::from(&header[..]);
+        record_bytes.append(&mut body);
+
+        // `deserialize` has an invariant that the slice needs to be long enough to make it a
+        // valid record, which in this case our slice is exactly as long as specified in the
+        // length field.
+        handle_record(record_bytes.as_slice(), &mut state)?;
+    }
+    debug!("saw the end of the response");
+    tls_stream.shutdown().await?;
+
+    let aead_scheme = require_negotiated_aead_scheme(&state)?;
+
+    let keys = records::gen_key(tls_stream.get_mut().1, negotiated_aead(aead_scheme)?).unwrap();
+
     Ok(NtsKeResult {
         aead_scheme,
         cookies: state.cookies,
@@ -201,3 +438,38 @@ pub fn run_nts_ke_client(client_config: ClientConfig) -> Result<NtsKeResult, Box
         use_ipv6: client_config.use_ipv6,
     })
 }
+
+/// Runs many NTS-KE handshakes concurrently, one per `ClientConfig`, and
+/// returns their results in the order they complete. At most `concurrency`
+/// handshakes are ever in flight at once, so querying an entire NTS pool
+/// doesn't open an unbounded number of sockets at the same time.
+pub async fn run_nts_ke_client_pool(
+    client_configs: Vec<ClientConfig>,
+    concurrency: usize,
+) -> Vec<Result<NtsKeResult, Box<dyn Error>>> {
+    stream::iter(client_configs)
+        .map(run_nts_ke_client_async)
+        .buffer_unordered(concurrency)
+        .collect()
+        .await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // The handshake functions themselves need a live NTS-KE server to
+    // exercise (negotiation, async/pool concurrency, session resumption,
+    // and client-auth wiring aren't covered here for that reason);
+    // `negotiated_aead` is pure, so it is unit tested directly.
+    #[test]
+    fn test_negotiated_aead_accepts_a_known_scheme() {
+        let scheme = negotiated_aead(KnownAeadAlgorithm::AeadAesSivCmac256 as u16).unwrap();
+        assert_eq!(scheme, KnownAeadAlgorithm::AeadAesSivCmac256);
+    }
+
+    #[test]
+    fn test_negotiated_aead_rejects_an_unknown_scheme() {
+        assert!(negotiated_aead(u16::MAX).is_err());
+    }
+}
@@ -1,5 +1,6 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::io;
+use std::path::Path;
 use std::sync::{Arc, RwLock};
 use std::thread;
 use std::time;
@@ -7,6 +8,10 @@ use std::time::SystemTime;
 
 use memcache;
 use memcache::MemcacheError;
+use redis;
+use redis::RedisError;
+use rusqlite;
+use rusqlite::{Connection, OptionalExtension};
 
 use lazy_static::lazy_static;
 use prometheus::{opts, register_counter, register_int_counter, IntCounter, Opts};
@@ -24,15 +29,47 @@ lazy_static! {
     )
     .unwrap();
 }
-pub type KeyID = [u8; 4];
+/// Identifies one of the (small, overlapping) set of master keys a server
+/// has configured. Embedded as the leading byte of a `KeyID` so that a
+/// server decrypting a presented cookie knows which master key it was
+/// wrapped under without having to try them all.
+pub type MasterKeyID = u8;
+
+/// `master_key_id` followed by the big-endian epoch bytes from `be_bytes`.
+pub type KeyID = [u8; 5];
+
+/// Prepends `master_key_id` to the epoch bytes to form a composite `KeyID`.
+fn key_id(master_key_id: MasterKeyID, epoch_bytes: [u8; 4]) -> KeyID {
+    let mut id = [0u8; 5];
+    id[0] = master_key_id;
+    id[1..].copy_from_slice(&epoch_bytes);
+    id
+}
+
+/// Which `KeyStore` implementation `RotatingKeys` should use, and how to
+/// reach it. Selected from server configuration so operators aren't forced
+/// to stand up memcached just to run the NTS-KE server.
+pub enum KeyStoreConfig {
+    Memcache { url: String },
+    Redis { url: String },
+    Sqlite { path: String },
+}
 
 pub struct RotatingKeys {
-    pub memcache_url: String,
+    pub key_store: KeyStoreConfig,
     pub prefix: String,
     pub duration: i64,
     pub forward_periods: i64,
     pub backward_periods: i64,
-    pub master_key: Vec<u8>,
+    /// Ordered set of master keys, oldest first and newest (current) last.
+    /// New cookies are always wrapped with `master_keys.last()`, but every
+    /// key in the set remains valid for decrypting cookies wrapped under it
+    /// while it's still configured. To rotate the master key without
+    /// breaking outstanding cookies, push a new entry, wait one
+    /// `duration * (forward_periods + backward_periods)` window so every
+    /// live cookie has been reissued under the new key, then drop the old
+    /// entry.
+    pub master_keys: Vec<(MasterKeyID, Vec<u8>)>,
     pub latest: KeyID,
     pub keys: HashMap<KeyID, Vec<u8>>,
     pub logger: slog::Logger,
@@ -52,67 +89,196 @@ fn be_bytes(n: i64) -> [u8; 4] {
     ret
 }
 
-trait VecMap {
-    fn get(&mut self, key: &str) -> Result<Option<Vec<u8>>, MemcacheError>;
+/// A pluggable storage backend for the per-epoch symmetric keys that
+/// `RotatingKeys` rotates through. Any backend that can persist opaque byte
+/// blobs under a string key can back the NTS-KE server, not just memcache.
+pub trait KeyStore {
+    type Error: std::error::Error + 'static;
+
+    /// Fetches the value stored at `key`, if any.
+    fn get(&mut self, key: &str) -> Result<Option<Vec<u8>>, Self::Error>;
+
+    /// Stores `value` at `key`, creating it if it doesn't already exist. Lets
+    /// servers bootstrap keys into a fresh backend.
+    fn put(&mut self, key: &str, value: Vec<u8>) -> Result<(), Self::Error>;
 }
 
-struct MemcacheVecMap {
+pub struct MemcacheKeyStore {
     client: memcache::Client,
 }
 
-impl VecMap for MemcacheVecMap {
+impl MemcacheKeyStore {
+    pub fn connect(url: &str) -> Result<Self, MemcacheError> {
+        Ok(MemcacheKeyStore {
+            client: memcache::Client::connect(url)?,
+        })
+    }
+}
+
+impl KeyStore for MemcacheKeyStore {
+    type Error = MemcacheError;
+
     fn get(&mut self, key: &str) -> Result<Option<Vec<u8>>, MemcacheError> {
         self.client.get::<Vec<u8>>(key)
     }
+
+    fn put(&mut self, key: &str, value: Vec<u8>) -> Result<(), MemcacheError> {
+        self.client.set(key, value, 0)
+    }
+}
+
+pub struct RedisKeyStore {
+    conn: redis::Connection,
+}
+
+impl RedisKeyStore {
+    pub fn connect(url: &str) -> Result<Self, RedisError> {
+        let client = redis::Client::open(url)?;
+        Ok(RedisKeyStore {
+            conn: client.get_connection()?,
+        })
+    }
+}
+
+impl KeyStore for RedisKeyStore {
+    type Error = RedisError;
+
+    fn get(&mut self, key: &str) -> Result<Option<Vec<u8>>, RedisError> {
+        redis::cmd("GET").arg(key).query(&mut self.conn)
+    }
+
+    fn put(&mut self, key: &str, value: Vec<u8>) -> Result<(), RedisError> {
+        redis::cmd("SET").arg(key).arg(value).query(&mut self.conn)
+    }
+}
+
+/// A local, dependency-light backend that persists keys to a SQLite file.
+/// Useful for single-server deployments that would rather not run memcached
+/// or Redis at all.
+pub struct SqliteKeyStore {
+    conn: Connection,
+}
+
+impl SqliteKeyStore {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, rusqlite::Error> {
+        let conn = Connection::open(path)?;
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS keys (k TEXT PRIMARY KEY, v BLOB NOT NULL)",
+            [],
+        )?;
+        Ok(SqliteKeyStore { conn })
+    }
+}
+
+impl KeyStore for SqliteKeyStore {
+    type Error = rusqlite::Error;
+
+    fn get(&mut self, key: &str) -> Result<Option<Vec<u8>>, rusqlite::Error> {
+        self.conn
+            .query_row("SELECT v FROM keys WHERE k = ?1", [key], |row| row.get(0))
+            .optional()
+    }
+
+    fn put(&mut self, key: &str, value: Vec<u8>) -> Result<(), rusqlite::Error> {
+        self.conn.execute(
+            "INSERT INTO keys (k, v) VALUES (?1, ?2) ON CONFLICT(k) DO UPDATE SET v = excluded.v",
+            rusqlite::params![key, value],
+        )?;
+        Ok(())
+    }
 }
 
 impl RotatingKeys {
     pub fn rotate_keys(&mut self) -> Result<(), Box<std::error::Error>> {
         ROTATION_COUNTER.inc();
-        let mut client = memcache::Client::connect(self.memcache_url.clone())?;
         let now = SystemTime::now().duration_since(std::time::UNIX_EPOCH)?;
         let timestamp = now.as_secs() as i64;
-        let mut vecmap = MemcacheVecMap { client: client };
-        self.internal_rotate(&mut vecmap, timestamp)
+        match &self.key_store {
+            KeyStoreConfig::Memcache { url } => {
+                let mut store = MemcacheKeyStore::connect(url)?;
+                self.internal_rotate(&mut store, timestamp)
+            }
+            KeyStoreConfig::Redis { url } => {
+                let mut store = RedisKeyStore::connect(url)?;
+                self.internal_rotate(&mut store, timestamp)
+            }
+            KeyStoreConfig::Sqlite { path } => {
+                let mut store = SqliteKeyStore::open(path)?;
+                self.internal_rotate(&mut store, timestamp)
+            }
+        }
     }
 
-    fn internal_rotate(
+    fn internal_rotate<S: KeyStore>(
         &mut self,
-        client: &mut dyn VecMap,
+        store: &mut S,
         timestamp: i64,
     ) -> Result<(), Box<std::error::Error>> {
         let mut failed = false;
+        let latest_master_key_id = self.latest_master_key_id()?;
         for i in -self.backward_periods..(self.forward_periods + 1) {
             let epoch = self.epoch(timestamp, i);
             let db_loc = format!("{}/{}", self.prefix, epoch);
-            let db_val = client.get(&db_loc)?;
-            let key_id = be_bytes(epoch);
+            let db_val = store
+                .get(&db_loc)
+                .map_err(|err| Box::new(err) as Box<std::error::Error>)?;
+            let epoch_bytes = be_bytes(epoch);
             match db_val {
                 Some(s) => {
-                    self.keys.insert(key_id, self.compute_wrap(s));
+                    // Wrap this epoch's value once per active master key so a
+                    // cookie wrapped under any currently-configured master
+                    // key can still be decrypted.
+                    for (master_key_id, master_key) in &self.master_keys {
+                        let wrapped = self.compute_wrap(master_key, &s);
+                        self.keys.insert(key_id(*master_key_id, epoch_bytes), wrapped);
+                    }
                 }
                 None => {
                     FAILURE_COUNTER.inc();
-                    error!(self.logger, "cannot read from memcache"; "key"=>db_loc, "memcache_url"=>self.memcache_url.clone());
+                    error!(self.logger, "cannot read from key store"; "key"=>db_loc);
                     failed = true;
                 }
             }
         }
+        let expired_epoch_bytes = be_bytes(self.epoch(timestamp, -self.backward_periods - 1));
+        for (master_key_id, _) in &self.master_keys {
+            self.keys.remove(&key_id(*master_key_id, expired_epoch_bytes));
+        }
+        // An operator may have retired a master key by dropping it from
+        // `master_keys` since the last rotation. Sweep out anything still
+        // wrapped under a master key id that's no longer configured so
+        // retirement actually revokes that key material instead of leaving
+        // it decryptable forever.
+        let active_master_key_ids: HashSet<MasterKeyID> =
+            self.master_keys.iter().map(|(id, _)| *id).collect();
         self.keys
-            .remove(&be_bytes(self.epoch(timestamp, -self.backward_periods - 1)));
-        self.latest = be_bytes(self.epoch(timestamp, 0)); // Not all of our friends may have gotten the same forwards keys as we did
+            .retain(|key_id, _| active_master_key_ids.contains(&key_id[0]));
+        // Not all of our friends may have gotten the same forwards keys as we did
+        self.latest = key_id(latest_master_key_id, be_bytes(self.epoch(timestamp, 0)));
         if failed {
             return Err(
-                io::Error::new(io::ErrorKind::Other, "A request to memcached failed").into(),
+                io::Error::new(io::ErrorKind::Other, "A request to the key store failed").into(),
             );
         } else {
             return Ok(());
         }
     }
 
-    fn compute_wrap(&self, val: Vec<u8>) -> Vec<u8> {
-        let key = hmac::SigningKey::new(&digest::SHA256, &self.master_key);
-        hmac::sign(&key, &val).as_ref().to_vec()
+    fn compute_wrap(&self, master_key: &[u8], val: &[u8]) -> Vec<u8> {
+        let key = hmac::SigningKey::new(&digest::SHA256, master_key);
+        hmac::sign(&key, val).as_ref().to_vec()
+    }
+
+    /// The id of the master key new cookies should be wrapped with.
+    fn latest_master_key_id(&self) -> Result<MasterKeyID, Box<std::error::Error>> {
+        match self.master_keys.last() {
+            Some((master_key_id, _)) => Ok(*master_key_id),
+            None => Err(io::Error::new(
+                io::ErrorKind::Other,
+                "RotatingKeys must be configured with at least one master key",
+            )
+            .into()),
+        }
     }
 
     fn epoch(&self, seconds: i64, offset: i64) -> i64 {
@@ -146,21 +312,28 @@ mod test {
     use super::*;
     use std::collections::HashMap;
 
-    struct HashMapVecMap {
+    struct HashMapKeyStore {
         table: HashMap<String, Option<Vec<u8>>>,
     }
 
-    impl VecMap for HashMapVecMap {
-        fn get(&mut self, key: &str) -> Result<Option<Vec<u8>>, MemcacheError> {
+    impl KeyStore for HashMapKeyStore {
+        type Error = io::Error;
+
+        fn get(&mut self, key: &str) -> Result<Option<Vec<u8>>, io::Error> {
             Ok(self.table[&key.to_owned()].clone())
         }
+
+        fn put(&mut self, key: &str, value: Vec<u8>) -> Result<(), io::Error> {
+            self.table.insert(key.to_owned(), Some(value));
+            Ok(())
+        }
     }
 
     #[test]
     fn test_rotation() {
         use sloggers::null::NullLoggerBuilder;
         use sloggers::Build;
-        let mut testmap = HashMapVecMap {
+        let mut testmap = HashMapKeyStore {
             table: HashMap::new(),
         };
         testmap
@@ -179,13 +352,15 @@ mod test {
         testmap.table.insert("test/0".to_string(), None);
 
         let mut test_rotor = RotatingKeys {
-            memcache_url: "unused".to_owned(),
+            key_store: KeyStoreConfig::Sqlite {
+                path: "unused".to_owned(),
+            },
             prefix: "test".to_owned(),
             duration: 1,
             forward_periods: 1,
             backward_periods: 1,
-            master_key: vec![0, 32],
-            latest: [1, 2, 3, 4],
+            master_keys: vec![(0, vec![0, 32])],
+            latest: [0, 1, 2, 3, 4],
             keys: HashMap::new(),
             logger: NullLoggerBuilder.build().unwrap(),
         };
@@ -203,4 +378,114 @@ mod test {
             panic!("Success should not have happened!")
         }
     }
-}
\ No newline at end of file
+
+    fn new_test_rotor(master_keys: Vec<(MasterKeyID, Vec<u8>)>) -> RotatingKeys {
+        use sloggers::null::NullLoggerBuilder;
+        use sloggers::Build;
+        RotatingKeys {
+            key_store: KeyStoreConfig::Sqlite {
+                path: "unused".to_owned(),
+            },
+            prefix: "test".to_owned(),
+            duration: 1,
+            forward_periods: 1,
+            backward_periods: 1,
+            master_keys,
+            latest: [0, 0, 0, 0, 0],
+            keys: HashMap::new(),
+            logger: NullLoggerBuilder.build().unwrap(),
+        }
+    }
+
+    fn filled_testmap() -> HashMapKeyStore {
+        let mut testmap = HashMapKeyStore {
+            table: HashMap::new(),
+        };
+        for epoch in 0..=5 {
+            testmap
+                .table
+                .insert(format!("test/{}", epoch), Some(vec![epoch as u8; 32]));
+        }
+        testmap
+    }
+
+    #[test]
+    fn test_rotation_wraps_under_every_active_master_key() {
+        let mut testmap = filled_testmap();
+        let mut test_rotor = new_test_rotor(vec![(0, vec![0, 32]), (1, vec![1, 32])]);
+
+        test_rotor.internal_rotate(&mut testmap, 2).unwrap();
+
+        // Every epoch currently in range should have been wrapped once per
+        // active master key, and new cookies should be keyed under the
+        // newest (last) master key.
+        for epoch in 1..=3 {
+            let epoch_bytes = be_bytes(epoch);
+            assert!(test_rotor.keys.contains_key(&key_id(0, epoch_bytes)));
+            assert!(test_rotor.keys.contains_key(&key_id(1, epoch_bytes)));
+        }
+        assert_eq!(test_rotor.latest[0], 1);
+    }
+
+    #[test]
+    fn test_rotation_purges_keys_for_a_retired_master_key() {
+        let mut testmap = filled_testmap();
+        let mut test_rotor = new_test_rotor(vec![(0, vec![0, 32]), (1, vec![1, 32])]);
+        test_rotor.internal_rotate(&mut testmap, 2).unwrap();
+        assert!(test_rotor
+            .keys
+            .keys()
+            .any(|key_id| key_id[0] == 0));
+
+        // Operator retires master key 0.
+        test_rotor.master_keys = vec![(1, vec![1, 32])];
+        test_rotor.internal_rotate(&mut testmap, 2).unwrap();
+
+        assert!(
+            !test_rotor.keys.keys().any(|key_id| key_id[0] == 0),
+            "cookies wrapped under a retired master key must not remain decryptable"
+        );
+    }
+
+    #[test]
+    fn test_rotation_against_sqlite_key_store() {
+        // `internal_rotate` is generic over `KeyStore`; exercise it against a
+        // real backend rather than only the in-memory `HashMapKeyStore`
+        // fake. SQLite is the one backend that needs no live service, so
+        // `:memory:` can back this directly.
+        let mut store = SqliteKeyStore::open(":memory:").unwrap();
+        for epoch in 0..=5 {
+            store
+                .put(&format!("test/{}", epoch), vec![epoch as u8; 32])
+                .unwrap();
+        }
+        let mut test_rotor = new_test_rotor(vec![(0, vec![0, 32]), (1, vec![1, 32])]);
+
+        test_rotor.internal_rotate(&mut store, 2).unwrap();
+
+        for epoch in 1..=3 {
+            let epoch_bytes = be_bytes(epoch);
+            assert!(test_rotor.keys.contains_key(&key_id(0, epoch_bytes)));
+            assert!(test_rotor.keys.contains_key(&key_id(1, epoch_bytes)));
+        }
+        assert_eq!(test_rotor.latest[0], 1);
+
+        // A read for an epoch that was never written to the store should
+        // surface as a rotation failure rather than panicking.
+        let err = test_rotor.internal_rotate(&mut store, 10);
+        assert!(err.is_err());
+    }
+
+    #[test]
+    fn test_sqlite_key_store_round_trips_values() {
+        let mut store = SqliteKeyStore::open(":memory:").unwrap();
+        assert_eq!(store.get("missing").unwrap(), None);
+
+        store.put("test/1", vec![1, 2, 3]).unwrap();
+        assert_eq!(store.get("test/1").unwrap(), Some(vec![1, 2, 3]));
+
+        // `put` on an existing key overwrites rather than erroring.
+        store.put("test/1", vec![4, 5, 6]).unwrap();
+        assert_eq!(store.get("test/1").unwrap(), Some(vec![4, 5, 6]));
+    }
+}